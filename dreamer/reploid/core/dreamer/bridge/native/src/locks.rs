@@ -0,0 +1,50 @@
+//! Advisory inter-process locking around mmap'd file access.
+//!
+//! Once writes are in play, multiple bridge processes (one per browser
+//! profile) can map the same file concurrently and corrupt each other's
+//! writes. Take a shared `flock` for reads and an exclusive one for writes,
+//! scoped to the same `File` used for the mmap itself, so the lock is
+//! released the moment that file is closed rather than needing its own
+//! teardown path.
+
+use std::fs::File;
+use std::io;
+
+use fs2::FileExt;
+
+/// A held `flock` on `file`. Released when dropped (or, per `flock`
+/// semantics, if every fd referring to the file is closed first).
+pub struct PathLock {
+    file: File,
+}
+
+impl PathLock {
+    /// Takes a non-blocking shared (read) lock, for `CMD_READ` / `CMD_STAT`.
+    pub fn acquire_shared(file: File) -> io::Result<Self> {
+        file.try_lock_shared()?;
+        Ok(PathLock { file })
+    }
+
+    /// Takes a non-blocking exclusive (write) lock, for `CMD_WRITE` /
+    /// `CMD_TRUNCATE` / `CMD_CREATE`.
+    pub fn acquire_exclusive(file: File) -> io::Result<Self> {
+        file.try_lock_exclusive()?;
+        Ok(PathLock { file })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for PathLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// True if `try_lock_shared`/`try_lock_exclusive` failed because someone
+/// else is holding a conflicting lock, as opposed to a real I/O error.
+pub fn is_contended(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
@@ -2,33 +2,128 @@
 //! Phase 3: Native file access for LLM inference
 //!
 //! Communicates with Chrome extension via native messaging protocol.
-//! Provides mmap-based file access to bypass browser storage limits.
+//! Provides mmap-based read/write file access to bypass browser storage limits.
 
-use std::io::{self, Read, Write};
-use std::fs::File;
+mod locks;
+mod wire;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor, Read, Write};
+use std::fs::{File, OpenOptions};
 use std::path::Path;
-use memmap2::MmapOptions;
+use memmap2::{Mmap, MmapOptions};
+use wire::{
+    ChunkManifestEntryHeader, DirEntryHeader, Header, ReadDirRequestHeader, ReadRequestHeader,
+    ReadResponseHeader, StatResponseHeader, TruncateRequestHeader, WireFormat, WriteRequestHeader,
+};
 
 // Protocol constants
 const MAGIC: u32 = 0x5245504C; // "REPL"
 const HEADER_SIZE: usize = 16;
 const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MB
+const MAX_NATIVE_MESSAGE_SIZE: usize = 1024 * 1024; // Chrome native messaging cap
 
 // Commands
 const CMD_PING: u8 = 0x00;
 const CMD_PONG: u8 = 0x01;
 const CMD_READ: u8 = 0x02;
 const CMD_READ_RESPONSE: u8 = 0x03;
+const CMD_WRITE: u8 = 0x04;
+const CMD_WRITE_RESPONSE: u8 = 0x05;
+const CMD_TRUNCATE: u8 = 0x06;
+const CMD_CREATE: u8 = 0x07;
+const CMD_READ_DIR: u8 = 0x08;
+const CMD_READ_DIR_RESPONSE: u8 = 0x09;
+const CMD_STAT: u8 = 0x0A;
+const CMD_STAT_RESPONSE: u8 = 0x0B;
 const CMD_ERROR: u8 = 0xFF;
 
+// CMD_STAT splits a file into fixed-size, content-addressed chunks aligned
+// to MAX_CHUNK_SIZE so a CMD_READ of any one chunk lines up with its hash.
+const STAT_CHUNK_SIZE: u64 = MAX_CHUNK_SIZE as u64;
+
+// Directory entry type flags (CMD_READ_DIR_RESPONSE)
+const ENTRY_TYPE_FILE: u8 = 0;
+const ENTRY_TYPE_DIR: u8 = 1;
+const ENTRY_TYPE_SYMLINK: u8 = 2;
+
 // Flags
 const FLAG_LAST_CHUNK: u8 = 0x02;
+const FLAG_COMPRESSED: u8 = 0x04;
+
+// Sliding-window flow control for CMD_READ: how much of a transfer is
+// allowed to be in flight (sent but not yet ack'd) at once.
+const MAX_INFLIGHT_CHUNKS: usize = 4;
+const MAX_INFLIGHT_BYTES: u64 = 32 * 1024 * 1024; // 32MB
+
+// Per-message cap for CMD_READ_RESPONSE, distinct from MAX_CHUNK_SIZE (which
+// governs CMD_STAT's manifest alignment, not wire transfer size). Each byte
+// goes out JSON-array-encoded as ASCII decimal digits plus a separator,
+// inflating the raw payload by roughly 4x, so this is kept well under
+// MAX_NATIVE_MESSAGE_SIZE once that inflation and the frame header are
+// accounted for.
+const MAX_READ_CHUNK_SIZE: u64 = 200 * 1024; // 200KB raw
+
+// Upper bound on `offset + data_len` for CMD_WRITE: guards against a
+// crafted offset near u64::MAX wrapping the required-length arithmetic
+// around to something small.
+const MAX_WRITE_FILE_SIZE: u64 = 1 << 40; // 1TB
+
+// Capability bits, exchanged in the CMD_PING / CMD_PONG handshake
+const CAP_COMPRESSION: u32 = 0x01;
+const HOST_CAPABILITIES: u32 = CAP_COMPRESSION;
 
 // Error codes
 const ERR_NOT_FOUND: u32 = 1;
 const ERR_PERMISSION_DENIED: u32 = 2;
 const ERR_IO_ERROR: u32 = 3;
 const ERR_INVALID_REQUEST: u32 = 4;
+const ERR_LOCKED: u32 = 5;
+
+/// Tracks one in-progress `CMD_READ` transfer that is larger than the
+/// sliding window, so it can be resumed as `"ack"` messages free up room.
+struct InFlightRead {
+    mmap: Mmap,
+    compress: bool,
+    /// Next byte offset to send.
+    next_offset: u64,
+    /// Exclusive end of the requested range.
+    end_offset: u64,
+    /// Chunks sent but not yet ack'd, oldest first: (offset, len).
+    unacked: VecDeque<(u64, u64)>,
+    unacked_bytes: u64,
+    /// Held for the lifetime of the transfer; released when this struct is
+    /// dropped (i.e. when `state.reads.remove(&req_id)` tears it down).
+    _lock: locks::PathLock,
+}
+
+impl InFlightRead {
+    fn is_done(&self) -> bool {
+        self.next_offset >= self.end_offset && self.unacked.is_empty()
+    }
+}
+
+/// Per-connection state that persists across native messages. There is one
+/// bridge process per extension connection, so a single instance living on
+/// the stack in `main` is enough to track what was negotiated in the
+/// handshake and which reads are mid-transfer.
+struct ConnectionState {
+    /// Capabilities both sides agreed on during the CMD_PING/CMD_PONG
+    /// handshake (see `CAP_*`). Starts empty until the extension pings.
+    negotiated_capabilities: u32,
+    /// In-flight `CMD_READ` transfers, keyed by `req_id`.
+    reads: HashMap<u32, InFlightRead>,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        ConnectionState { negotiated_capabilities: 0, reads: HashMap::new() }
+    }
+
+    fn compression_enabled(&self) -> bool {
+        self.negotiated_capabilities & CAP_COMPRESSION != 0
+    }
+}
 
 fn main() {
     // Native messaging uses stdin/stdout
@@ -37,6 +132,7 @@ fn main() {
 
     let mut stdin_lock = stdin.lock();
     let mut stdout_lock = stdout.lock();
+    let mut state = ConnectionState::new();
 
     loop {
         // Read message length (4 bytes, native byte order)
@@ -46,7 +142,7 @@ fn main() {
         }
         let msg_len = u32::from_ne_bytes(len_buf) as usize;
 
-        if msg_len == 0 || msg_len > 1024 * 1024 {
+        if msg_len == 0 || msg_len > MAX_NATIVE_MESSAGE_SIZE {
             eprintln!("[TitanBridge] Invalid message length: {}", msg_len);
             continue;
         }
@@ -67,9 +163,10 @@ fn main() {
             }
         };
 
-        // Handle message
-        if let Some(response) = handle_message(&msg) {
-            // Write response
+        // Handle message. A single incoming message can enqueue several
+        // outbound frames (e.g. a CMD_READ filling its whole send window),
+        // so write each in turn.
+        for response in handle_message(&msg, &mut state) {
             let response_bytes = serde_json::to_vec(&response).unwrap();
             let len_bytes = (response_bytes.len() as u32).to_ne_bytes();
             stdout_lock.write_all(&len_bytes).unwrap();
@@ -79,28 +176,34 @@ fn main() {
     }
 }
 
-fn handle_message(msg: &serde_json::Value) -> Option<serde_json::Value> {
-    let msg_type = msg.get("type")?.as_str()?;
+fn handle_message(msg: &serde_json::Value, state: &mut ConnectionState) -> Vec<serde_json::Value> {
+    let msg_type = match msg.get("type").and_then(|v| v.as_str()) {
+        Some(t) => t,
+        None => return Vec::new(),
+    };
 
     match msg_type {
         "binary" => {
-            let data = msg.get("data")?.as_array()?;
+            let data = match msg.get("data").and_then(|v| v.as_array()) {
+                Some(d) => d,
+                None => return Vec::new(),
+            };
             let bytes: Vec<u8> = data.iter().filter_map(|v| v.as_u64().map(|n| n as u8)).collect();
 
             if bytes.len() < HEADER_SIZE {
-                return Some(create_error_response(0, ERR_INVALID_REQUEST, "Message too short"));
+                return vec![create_error_response(0, ERR_INVALID_REQUEST, "Message too short")];
             }
 
             // Parse header
-            let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-            if magic != MAGIC {
-                return Some(create_error_response(0, ERR_INVALID_REQUEST, "Invalid magic"));
+            let mut cursor = Cursor::new(&bytes[..HEADER_SIZE]);
+            let header = Header::decode(&mut cursor).expect("header slice is exactly HEADER_SIZE bytes");
+            if header.magic != MAGIC {
+                return vec![create_error_response(0, ERR_INVALID_REQUEST, "Invalid magic")];
             }
 
-            let cmd = bytes[4];
-            let _flags = bytes[5];
-            let req_id = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
-            let payload_len = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]) as usize;
+            let cmd = header.cmd;
+            let req_id = header.req_id;
+            let payload_len = header.payload_len as usize;
 
             let payload = if payload_len > 0 && bytes.len() >= HEADER_SIZE + payload_len {
                 &bytes[HEADER_SIZE..HEADER_SIZE + payload_len]
@@ -109,98 +212,561 @@ fn handle_message(msg: &serde_json::Value) -> Option<serde_json::Value> {
             };
 
             match cmd {
-                CMD_PING => Some(create_pong_response(req_id)),
-                CMD_READ => handle_read_request(req_id, payload),
-                _ => Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Unknown command")),
+                CMD_PING => {
+                    // Payload: client_capabilities: u32. Negotiated capabilities are
+                    // whatever both the client and this host support.
+                    let client_capabilities = if payload.len() >= 4 {
+                        u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]])
+                    } else {
+                        0
+                    };
+                    state.negotiated_capabilities = client_capabilities & HOST_CAPABILITIES;
+                    vec![create_pong_response(req_id, state.negotiated_capabilities)]
+                }
+                CMD_READ => handle_read_request(req_id, payload, state),
+                CMD_WRITE => handle_write_request(req_id, payload).into_iter().collect(),
+                CMD_TRUNCATE => handle_truncate_request(req_id, payload).into_iter().collect(),
+                CMD_CREATE => handle_create_request(req_id, payload).into_iter().collect(),
+                CMD_READ_DIR => handle_read_dir_request(req_id, payload).into_iter().collect(),
+                CMD_STAT => handle_stat_request(req_id, payload).into_iter().collect(),
+                _ => vec![create_error_response(req_id, ERR_INVALID_REQUEST, "Unknown command")],
             }
         }
         "ack" => {
-            // ACK for backpressure - just acknowledge receipt
-            None
+            let req_id = match msg.get("req_id").and_then(|v| v.as_u64()) {
+                Some(r) => r as u32,
+                None => return Vec::new(),
+            };
+            let acked_offset = match msg.get("offset").and_then(|v| v.as_u64()) {
+                Some(o) => o,
+                None => return Vec::new(),
+            };
+            handle_ack(state, req_id, acked_offset)
         }
         _ => {
             eprintln!("[TitanBridge] Unknown message type: {}", msg_type);
-            None
+            Vec::new()
         }
     }
 }
 
-fn handle_read_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
-    if payload.len() < 16 {
-        return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short"));
-    }
-
-    // Parse offset and length (u64 as two u32s)
-    let offset_low = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as u64;
-    let offset_high = u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]) as u64;
-    let offset = offset_low + (offset_high << 32);
-
-    let length_low = u32::from_le_bytes([payload[8], payload[9], payload[10], payload[11]]) as u64;
-    let length_high = u32::from_le_bytes([payload[12], payload[13], payload[14], payload[15]]) as u64;
-    let length = length_low + (length_high << 32);
+/// Starts a `CMD_READ` transfer. Rather than building every chunk up front,
+/// this registers an `InFlightRead` in `state` and fills only the initial
+/// send window; the rest is drained by `handle_ack` as the extension
+/// consumes what it's been sent.
+fn handle_read_request(req_id: u32, payload: &[u8], state: &mut ConnectionState) -> Vec<serde_json::Value> {
+    let mut cursor = Cursor::new(payload);
+    let header = match ReadRequestHeader::decode(&mut cursor) {
+        Ok(h) => h,
+        Err(_) => return vec![create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short")],
+    };
+    let offset = header.offset;
+    let length = header.length;
 
-    // Parse path
-    let path_bytes = &payload[16..];
-    let path = match std::str::from_utf8(path_bytes) {
+    // The path has no length prefix: it's simply everything left in the payload.
+    let path_bytes = match wire::read_to_end(&mut cursor) {
+        Ok(b) => b,
+        Err(_) => return vec![create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short")],
+    };
+    let path = match std::str::from_utf8(&path_bytes) {
         Ok(s) => s,
-        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+        Err(_) => return vec![create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")],
     };
 
     // Security: Only allow paths in allowed directories
     if !is_path_allowed(path) {
-        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+        return vec![create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory")];
     }
 
     // Open and mmap file
     let file = match File::open(path) {
         Ok(f) => f,
-        Err(e) => {
-            let code = if e.kind() == io::ErrorKind::NotFound {
-                ERR_NOT_FOUND
-            } else if e.kind() == io::ErrorKind::PermissionDenied {
-                ERR_PERMISSION_DENIED
-            } else {
-                ERR_IO_ERROR
-            };
-            return Some(create_error_response(req_id, code, &e.to_string()));
+        Err(e) => return vec![create_error_response(req_id, io_error_code(&e), &e.to_string())],
+    };
+
+    let lock = match locks::PathLock::acquire_shared(file) {
+        Ok(l) => l,
+        Err(e) if locks::is_contended(&e) => {
+            return vec![create_error_response(req_id, ERR_LOCKED, "Path is locked by another process")];
         }
+        Err(e) => return vec![create_error_response(req_id, ERR_IO_ERROR, &e.to_string())],
     };
 
-    let mmap = match unsafe { MmapOptions::new().map(&file) } {
+    let mmap = match unsafe { MmapOptions::new().map(lock.file()) } {
         Ok(m) => m,
-        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+        Err(e) => return vec![create_error_response(req_id, ERR_IO_ERROR, &e.to_string())],
     };
 
     let file_len = mmap.len() as u64;
     if offset >= file_len {
-        return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Offset beyond file end"));
+        return vec![create_error_response(req_id, ERR_INVALID_REQUEST, "Offset beyond file end")];
     }
 
-    let actual_length = std::cmp::min(length, file_len - offset) as usize;
-    let data = &mmap[offset as usize..(offset as usize + actual_length)];
+    let actual_length = std::cmp::min(length, file_len - offset);
+    let compress = state.compression_enabled();
+
+    state.reads.insert(req_id, InFlightRead {
+        mmap,
+        compress,
+        next_offset: offset,
+        end_offset: offset + actual_length,
+        unacked: VecDeque::new(),
+        unacked_bytes: 0,
+        _lock: lock,
+    });
+
+    send_window(state, req_id)
+}
+
+/// Sends as many chunks of `req_id`'s in-flight read as the window allows,
+/// advancing `next_offset` and tracking them as unacked. Returns the frames
+/// to write; the transfer is dropped from `state.reads` once it is done and
+/// every sent chunk has been ack'd.
+fn send_window(state: &mut ConnectionState, req_id: u32) -> Vec<serde_json::Value> {
+    let mut responses = Vec::new();
+
+    let read = match state.reads.get_mut(&req_id) {
+        Some(r) => r,
+        None => return responses,
+    };
+
+    while read.unacked.len() < MAX_INFLIGHT_CHUNKS
+        && read.unacked_bytes < MAX_INFLIGHT_BYTES
+        && read.next_offset < read.end_offset
+    {
+        let chunk_offset = read.next_offset;
+        let chunk_len = std::cmp::min(MAX_READ_CHUNK_SIZE, read.end_offset - chunk_offset);
+        let chunk = &read.mmap[chunk_offset as usize..(chunk_offset + chunk_len) as usize];
+        let is_last = chunk_offset + chunk_len >= read.end_offset;
 
-    // Send response in chunks
-    let mut pos = 0;
-    while pos < actual_length {
-        let chunk_size = std::cmp::min(MAX_CHUNK_SIZE, actual_length - pos);
-        let chunk = &data[pos..pos + chunk_size];
-        let is_last = pos + chunk_size >= actual_length;
+        responses.push(create_read_response(req_id, chunk_offset, chunk, is_last, read.compress));
+
+        read.unacked.push_back((chunk_offset, chunk_len));
+        read.unacked_bytes += chunk_len;
+        read.next_offset += chunk_len;
+    }
 
-        let response = create_read_response(req_id, offset + pos as u64, chunk, is_last);
+    if read.is_done() {
+        state.reads.remove(&req_id);
+    }
+
+    responses
+}
 
-        // For multi-chunk responses, we need to send each chunk separately
-        // This is a simplified version - real implementation would wait for ACKs
-        pos += chunk_size;
+/// Handles an `"ack"` message: retires every unacked chunk the extension has
+/// now consumed (anything fully below `acked_offset`) and tops the window
+/// back up.
+fn handle_ack(state: &mut ConnectionState, req_id: u32, acked_offset: u64) -> Vec<serde_json::Value> {
+    let read = match state.reads.get_mut(&req_id) {
+        Some(r) => r,
+        None => return Vec::new(),
+    };
 
-        if is_last || pos >= actual_length {
-            return Some(response);
+    while let Some(&(chunk_offset, chunk_len)) = read.unacked.front() {
+        if chunk_offset + chunk_len > acked_offset {
+            break;
         }
+        read.unacked.pop_front();
+        read.unacked_bytes -= chunk_len;
     }
 
-    None
+    send_window(state, req_id)
+}
+
+/// Payload layout for `CMD_WRITE`:
+/// `offset: u64 | data_len: u64 | path_len: u16 | path: [u8; path_len] | data: [u8; data_len]`
+///
+/// The file is opened (creating it if missing) under `is_path_allowed_for_create`,
+/// the create-capable variant of the `is_path_allowed` gate reads use, grown via `set_len` if the write extends past the current
+/// end, then mapped writable so the incoming bytes can be copied in at
+/// `offset` and flushed back to disk.
+fn handle_write_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
+    let mut cursor = Cursor::new(payload);
+    let header = match WriteRequestHeader::decode(&mut cursor) {
+        Ok(h) => h,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short")),
+    };
+    let offset = header.offset;
+    let data_len = header.data_len;
+
+    let path_bytes = match wire::read_len_prefixed(&mut cursor) {
+        Ok(b) => b,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short for path")),
+    };
+    let path = match std::str::from_utf8(&path_bytes) {
+        Ok(s) => s,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+    };
+
+    let data = match wire::read_exact_len(&mut cursor, data_len as usize) {
+        Ok(d) => d,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short for data")),
+    };
+
+    if !is_path_allowed_for_create(path) {
+        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+    }
+
+    let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(create_error_response(req_id, io_error_code(&e), &e.to_string())),
+    };
+
+    let lock = match locks::PathLock::acquire_exclusive(file) {
+        Ok(l) => l,
+        Err(e) if locks::is_contended(&e) => {
+            return Some(create_error_response(req_id, ERR_LOCKED, "Path is locked by another process"));
+        }
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+    let file = lock.file();
+
+    let required_len = match offset.checked_add(data_len) {
+        Some(len) if len <= MAX_WRITE_FILE_SIZE => len,
+        _ => {
+            return Some(create_error_response(
+                req_id,
+                ERR_INVALID_REQUEST,
+                "Write offset + length exceeds the maximum supported file size",
+            ));
+        }
+    };
+    let current_len = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+    let grow_result = if required_len > current_len { file.set_len(required_len) } else { Ok(()) };
+    if let Err(e) = grow_result {
+        return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string()));
+    }
+
+    if data_len == 0 {
+        return Some(create_write_response(req_id, 0));
+    }
+
+    let mut mmap = match unsafe { MmapOptions::new().map_mut(file) } {
+        Ok(m) => m,
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    // required_len <= mmap.len() is guaranteed by the grow above, but check
+    // explicitly rather than trusting that invariant all the way to a slice
+    // index, the way the read path guards `offset >= file_len`.
+    if required_len > mmap.len() as u64 {
+        return Some(create_error_response(req_id, ERR_IO_ERROR, "File shorter than expected after growing"));
+    }
+
+    let start = offset as usize;
+    mmap[start..start + data.len()].copy_from_slice(&data);
+
+    if let Err(e) = mmap.flush() {
+        return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string()));
+    }
+
+    Some(create_write_response(req_id, data.len() as u64))
+}
+
+/// Payload layout for `CMD_TRUNCATE`: `new_len: u64 | path_len: u16 | path: [u8; path_len]`
+fn handle_truncate_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
+    let mut cursor = Cursor::new(payload);
+    let header = match TruncateRequestHeader::decode(&mut cursor) {
+        Ok(h) => h,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short")),
+    };
+    let new_len = header.new_len;
+
+    let path_bytes = match wire::read_len_prefixed(&mut cursor) {
+        Ok(b) => b,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short for path")),
+    };
+    let path = match std::str::from_utf8(&path_bytes) {
+        Ok(s) => s,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+    };
+
+    if !is_path_allowed_for_create(path) {
+        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+    }
+
+    let file = match OpenOptions::new().write(true).create(true).truncate(false).open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(create_error_response(req_id, io_error_code(&e), &e.to_string())),
+    };
+
+    let lock = match locks::PathLock::acquire_exclusive(file) {
+        Ok(l) => l,
+        Err(e) if locks::is_contended(&e) => {
+            return Some(create_error_response(req_id, ERR_LOCKED, "Path is locked by another process"));
+        }
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    if let Err(e) = lock.file().set_len(new_len) {
+        return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string()));
+    }
+
+    Some(create_write_response(req_id, new_len))
+}
+
+/// Payload layout for `CMD_CREATE`: `path_len: u16 | path: [u8; path_len]`
+///
+/// Creates an empty file at `path`, truncating it if it already exists.
+fn handle_create_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
+    let mut cursor = Cursor::new(payload);
+    let path_bytes = match wire::read_len_prefixed(&mut cursor) {
+        Ok(b) => b,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short for path")),
+    };
+    let path = match std::str::from_utf8(&path_bytes) {
+        Ok(s) => s,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+    };
+
+    if !is_path_allowed_for_create(path) {
+        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+    }
+
+    // Open without truncating so the lock is held before any destructive
+    // mutation, the same order `handle_write_request`/`handle_truncate_request`
+    // use: a reader holding the shared lock with an active mmap must not see
+    // the file truncated out from under it.
+    let file = match OpenOptions::new().write(true).create(true).truncate(false).open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(create_error_response(req_id, io_error_code(&e), &e.to_string())),
+    };
+
+    let lock = match locks::PathLock::acquire_exclusive(file) {
+        Ok(l) => l,
+        Err(e) if locks::is_contended(&e) => {
+            return Some(create_error_response(req_id, ERR_LOCKED, "Path is locked by another process"));
+        }
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    match lock.file().set_len(0) {
+        Ok(()) => Some(create_write_response(req_id, 0)),
+        Err(e) => Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    }
+}
+
+/// Payload layout for `CMD_READ_DIR`: `cursor: u64 | path_len: u16 | path: [u8; path_len]`
+///
+/// `cursor` is the offset into the directory's sorted entry list to resume
+/// from (0 on the first request). The response carries a `next_cursor` and
+/// sets `FLAG_LAST_CHUNK` once the listing is exhausted, so the extension can
+/// page through directories larger than the native messaging size cap.
+fn handle_read_dir_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
+    let mut byte_cursor = Cursor::new(payload);
+    let header = match ReadDirRequestHeader::decode(&mut byte_cursor) {
+        Ok(h) => h,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short")),
+    };
+    let cursor = header.cursor as usize;
+
+    let path_bytes = match wire::read_len_prefixed(&mut byte_cursor) {
+        Ok(b) => b,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Payload too short for path")),
+    };
+    let path = match std::str::from_utf8(&path_bytes) {
+        Ok(s) => s,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+    };
+
+    if !is_path_allowed(path) {
+        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+    }
+
+    let read_dir = match std::fs::read_dir(path) {
+        Ok(d) => d,
+        Err(e) => return Some(create_error_response(req_id, io_error_code(&e), &e.to_string())),
+    };
+
+    let mut entries: Vec<DirEntryInfo> = Vec::new();
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        let full_path = entry.path();
+        if !is_path_allowed(&full_path.to_string_lossy()) {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let entry_type = if metadata.is_symlink() {
+            ENTRY_TYPE_SYMLINK
+        } else if metadata.is_dir() {
+            ENTRY_TYPE_DIR
+        } else {
+            ENTRY_TYPE_FILE
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        entries.push(DirEntryInfo {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size: metadata.len(),
+            entry_type,
+            mtime,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if cursor > entries.len() {
+        return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Cursor beyond end of listing"));
+    }
+
+    // Budget entries into this chunk so the encoded message stays well under
+    // the native messaging size cap: the raw payload gets the same
+    // MAX_READ_CHUNK_SIZE budget as a CMD_READ_RESPONSE, which already allows
+    // for the JSON-array inflation.
+    let mut page: Vec<&DirEntryInfo> = Vec::new();
+    let mut payload_size = 8; // next_cursor
+    for entry in &entries[cursor..] {
+        let entry_size = 2 + entry.name.len() + 8 + 1 + 8;
+        if !page.is_empty() && payload_size + entry_size > MAX_READ_CHUNK_SIZE as usize {
+            break;
+        }
+        payload_size += entry_size;
+        page.push(entry);
+    }
+
+    let next_cursor = cursor + page.len();
+    let is_last = next_cursor >= entries.len();
+
+    Some(create_read_dir_response(req_id, next_cursor as u64, &page, is_last))
+}
+
+struct DirEntryInfo {
+    name: String,
+    size: u64,
+    entry_type: u8,
+    mtime: u64,
+}
+
+/// Payload layout for `CMD_STAT`: the path as raw bytes, same as `CMD_READ`'s
+/// path field (no length prefix, since nothing follows it).
+///
+/// Hashes the file's `STAT_CHUNK_SIZE`-aligned chunks with BLAKE3 directly
+/// over the read-only mmap, so the extension can diff the manifest against
+/// whatever it already has cached and only `CMD_READ` the ranges that
+/// changed, instead of re-fetching the whole file.
+fn handle_stat_request(req_id: u32, payload: &[u8]) -> Option<serde_json::Value> {
+    let path = match std::str::from_utf8(payload) {
+        Ok(s) => s,
+        Err(_) => return Some(create_error_response(req_id, ERR_INVALID_REQUEST, "Invalid path encoding")),
+    };
+
+    if !is_path_allowed(path) {
+        return Some(create_error_response(req_id, ERR_PERMISSION_DENIED, "Path not in allowed directory"));
+    }
+
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Some(create_error_response(req_id, io_error_code(&e), &e.to_string())),
+    };
+
+    let lock = match locks::PathLock::acquire_shared(file) {
+        Ok(l) => l,
+        Err(e) if locks::is_contended(&e) => {
+            return Some(create_error_response(req_id, ERR_LOCKED, "Path is locked by another process"));
+        }
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    let file_size = match lock.file().metadata() {
+        Ok(m) => m.len(),
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    // memmap2 errors on a zero-length mapping, so an empty file gets an
+    // empty manifest directly rather than going through the mmap at all.
+    if file_size == 0 {
+        let root_hash = *blake3::hash(&[]).as_bytes();
+        return Some(create_stat_response(req_id, 0, &[], root_hash));
+    }
+
+    let mmap = match unsafe { MmapOptions::new().map(lock.file()) } {
+        Ok(m) => m,
+        Err(e) => return Some(create_error_response(req_id, ERR_IO_ERROR, &e.to_string())),
+    };
+
+    let mut chunks = Vec::new();
+    let mut root_hasher = blake3::Hasher::new();
+    let mut offset = 0u64;
+    while offset < file_size {
+        let len = std::cmp::min(STAT_CHUNK_SIZE, file_size - offset);
+        let range = offset as usize..(offset + len) as usize;
+        let hash = *blake3::hash(&mmap[range]).as_bytes();
+        root_hasher.update(&hash);
+        chunks.push(ChunkManifestEntry { offset, len, hash });
+        offset += len;
+    }
+    let root_hash = *root_hasher.finalize().as_bytes();
+
+    Some(create_stat_response(req_id, file_size, &chunks, root_hash))
+}
+
+struct ChunkManifestEntry {
+    offset: u64,
+    len: u64,
+    hash: [u8; 32],
+}
+
+fn io_error_code(e: &io::Error) -> u32 {
+    if e.kind() == io::ErrorKind::NotFound {
+        ERR_NOT_FOUND
+    } else if e.kind() == io::ErrorKind::PermissionDenied {
+        ERR_PERMISSION_DENIED
+    } else {
+        ERR_IO_ERROR
+    }
+}
+
+/// Assembles a full frame (header + payload) into the JSON envelope Chrome's
+/// native messaging expects. Every `create_*_response` builds its payload
+/// bytes and hands them to this, so the header layout lives in exactly one
+/// place.
+fn build_frame(cmd: u8, flags: u8, req_id: u32, payload: &[u8]) -> serde_json::Value {
+    let header = Header {
+        magic: MAGIC,
+        cmd,
+        flags,
+        reserved: 0,
+        req_id,
+        payload_len: payload.len() as u32,
+    };
+
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    header.encode(&mut message).expect("encoding into a Vec cannot fail");
+    message.extend_from_slice(payload);
+
+    serde_json::json!({
+        "type": "binary",
+        "data": message
+    })
 }
 
 fn is_path_allowed(path: &str) -> bool {
+    is_path_allowed_in(path, false)
+}
+
+/// Like `is_path_allowed`, but for a path a create-capable command
+/// (`CMD_WRITE`/`CMD_TRUNCATE`/`CMD_CREATE`) may target before it exists.
+/// `Path::canonicalize` fails on a path that isn't there yet, so this
+/// canonicalizes the parent directory (which must already exist) instead of
+/// the target itself.
+fn is_path_allowed_for_create(path: &str) -> bool {
+    is_path_allowed_in(path, true)
+}
+
+fn is_path_allowed_in(path: &str, for_create: bool) -> bool {
     let path = Path::new(path);
 
     // Must be absolute path
@@ -220,9 +786,34 @@ fn is_path_allowed(path: &str) -> bool {
     for allowed in &allowed_dirs {
         if path.starts_with(allowed) {
             // Disallow path traversal
-            let canonical = match path.canonicalize() {
-                Ok(p) => p,
-                Err(_) => return false,
+            let canonical = if for_create {
+                let (parent, name) = match (path.parent(), path.file_name()) {
+                    (Some(p), Some(n)) => (p, n),
+                    _ => return false,
+                };
+                let canonical_parent = match parent.canonicalize() {
+                    Ok(p) => p,
+                    Err(_) => return false,
+                };
+                let candidate = canonical_parent.join(name);
+                // The final component can't be canonicalized up front (it may
+                // not exist yet), but if it already exists as a symlink it
+                // must still be fully resolved and re-checked — otherwise a
+                // symlink planted in an allowed, world-writable directory
+                // (e.g. `/tmp/evil -> /etc/passwd`) would pass this check
+                // and then get followed by `OpenOptions::open`.
+                match std::fs::symlink_metadata(&candidate) {
+                    Ok(meta) if meta.file_type().is_symlink() => match candidate.canonicalize() {
+                        Ok(resolved) => resolved,
+                        Err(_) => return false,
+                    },
+                    _ => candidate,
+                }
+            } else {
+                match path.canonicalize() {
+                    Ok(p) => p,
+                    Err(_) => return false,
+                }
             };
             return canonical.starts_with(allowed);
         }
@@ -231,63 +822,80 @@ fn is_path_allowed(path: &str) -> bool {
     false
 }
 
-fn create_pong_response(req_id: u32) -> serde_json::Value {
-    let mut header = vec![0u8; HEADER_SIZE];
-    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
-    header[4] = CMD_PONG;
-    header[5] = 0; // flags
-    header[8..12].copy_from_slice(&req_id.to_le_bytes());
-    header[12..16].copy_from_slice(&0u32.to_le_bytes()); // payload len
+fn create_pong_response(req_id: u32, negotiated_capabilities: u32) -> serde_json::Value {
+    build_frame(CMD_PONG, 0, req_id, &negotiated_capabilities.to_le_bytes())
+}
 
-    serde_json::json!({
-        "type": "binary",
-        "data": header
-    })
+/// Compresses `data` with zstd if `compress` is set and the result is
+/// actually smaller. Returns `(compressed, bytes)`.
+fn maybe_compress(data: &[u8], compress: bool) -> (bool, Vec<u8>) {
+    if !compress || data.is_empty() {
+        return (false, data.to_vec());
+    }
+
+    match zstd::stream::encode_all(data, 0) {
+        Ok(encoded) if encoded.len() < data.len() => (true, encoded),
+        _ => (false, data.to_vec()),
+    }
 }
 
-fn create_read_response(req_id: u32, offset: u64, data: &[u8], is_last: bool) -> serde_json::Value {
-    // Payload: offset (8 bytes) + data
-    let payload_len = 8 + data.len();
-    let mut message = vec![0u8; HEADER_SIZE + payload_len];
+fn create_read_response(req_id: u32, offset: u64, data: &[u8], is_last: bool, compress: bool) -> serde_json::Value {
+    // Each chunk is compressed independently (not across the whole file) so
+    // chunks stay decodable out of order.
+    let (compressed, body) = maybe_compress(data, compress);
 
-    // Header
-    message[0..4].copy_from_slice(&MAGIC.to_le_bytes());
-    message[4] = CMD_READ_RESPONSE;
-    message[5] = if is_last { FLAG_LAST_CHUNK } else { 0 };
-    message[8..12].copy_from_slice(&req_id.to_le_bytes());
-    message[12..16].copy_from_slice(&(payload_len as u32).to_le_bytes());
+    let header = ReadResponseHeader { offset, uncompressed_len: data.len() as u64 };
+    let mut payload = Vec::with_capacity(16 + body.len());
+    header.encode(&mut payload).expect("encoding into a Vec cannot fail");
+    payload.extend_from_slice(&body);
 
-    // Payload: offset
-    message[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
-    message[20..24].copy_from_slice(&((offset >> 32) as u32).to_le_bytes());
+    let flags = (if is_last { FLAG_LAST_CHUNK } else { 0 }) | (if compressed { FLAG_COMPRESSED } else { 0 });
+    build_frame(CMD_READ_RESPONSE, flags, req_id, &payload)
+}
 
-    // Payload: data
-    message[24..].copy_from_slice(data);
+fn create_write_response(req_id: u32, bytes_written: u64) -> serde_json::Value {
+    build_frame(CMD_WRITE_RESPONSE, 0, req_id, &bytes_written.to_le_bytes())
+}
 
-    serde_json::json!({
-        "type": "binary",
-        "data": message
-    })
+fn create_read_dir_response(req_id: u32, next_cursor: u64, entries: &[&DirEntryInfo], is_last: bool) -> serde_json::Value {
+    let mut payload = Vec::with_capacity(8 + entries.len() * 32);
+    payload.extend_from_slice(&next_cursor.to_le_bytes());
+    for entry in entries {
+        wire::write_len_prefixed(&mut payload, entry.name.as_bytes()).expect("encoding into a Vec cannot fail");
+        DirEntryHeader { size: entry.size, entry_type: entry.entry_type, mtime: entry.mtime }
+            .encode(&mut payload)
+            .expect("encoding into a Vec cannot fail");
+    }
+
+    let flags = if is_last { FLAG_LAST_CHUNK } else { 0 };
+    build_frame(CMD_READ_DIR_RESPONSE, flags, req_id, &payload)
 }
 
-fn create_error_response(req_id: u32, code: u32, message: &str) -> serde_json::Value {
-    let msg_bytes = message.as_bytes();
-    let payload_len = 4 + msg_bytes.len();
-    let mut response = vec![0u8; HEADER_SIZE + payload_len];
+fn create_stat_response(
+    req_id: u32,
+    file_size: u64,
+    chunks: &[ChunkManifestEntry],
+    root_hash: [u8; 32],
+) -> serde_json::Value {
+    let mut payload = Vec::with_capacity(12 + 32 + chunks.len() * 48);
+    StatResponseHeader { file_size, chunk_count: chunks.len() as u32 }
+        .encode(&mut payload)
+        .expect("encoding into a Vec cannot fail");
+    wire::write_hash(&mut payload, &root_hash).expect("encoding into a Vec cannot fail");
+    for chunk in chunks {
+        ChunkManifestEntryHeader { offset: chunk.offset, len: chunk.len }
+            .encode(&mut payload)
+            .expect("encoding into a Vec cannot fail");
+        wire::write_hash(&mut payload, &chunk.hash).expect("encoding into a Vec cannot fail");
+    }
 
-    // Header
-    response[0..4].copy_from_slice(&MAGIC.to_le_bytes());
-    response[4] = CMD_ERROR;
-    response[5] = 0;
-    response[8..12].copy_from_slice(&req_id.to_le_bytes());
-    response[12..16].copy_from_slice(&(payload_len as u32).to_le_bytes());
+    build_frame(CMD_STAT_RESPONSE, 0, req_id, &payload)
+}
 
-    // Payload: error code + message
-    response[16..20].copy_from_slice(&code.to_le_bytes());
-    response[20..].copy_from_slice(msg_bytes);
+fn create_error_response(req_id: u32, code: u32, message: &str) -> serde_json::Value {
+    let mut payload = Vec::with_capacity(4 + message.len());
+    payload.extend_from_slice(&code.to_le_bytes());
+    payload.extend_from_slice(message.as_bytes());
 
-    serde_json::json!({
-        "type": "binary",
-        "data": response
-    })
+    build_frame(CMD_ERROR, 0, req_id, &payload)
 }
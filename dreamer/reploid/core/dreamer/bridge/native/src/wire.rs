@@ -0,0 +1,235 @@
+//! Declarative wire-format layer for the native bridge protocol.
+//!
+//! Every command used to build and parse its frame by hand with
+//! `copy_from_slice`/`from_le_bytes` index arithmetic, which had already
+//! produced subtle offset bugs. `WireFormat` centralizes that: each command's
+//! header and payload become a plain struct with an `encode`/`decode` pair.
+//! `wire_format!` plays the role a derive macro would play in a workspace
+//! with a proc-macro crate of its own (this binary has none to put one in):
+//! given a struct of fixed-width integer fields, it generates the
+//! `WireFormat` impl in one place instead of repeating it per command.
+//! Variable-length fields (paths, messages, file data) are handled by small
+//! hand-written helper functions instead, since their length isn't known at
+//! the type level.
+
+use std::io::{self, Read, Write};
+
+pub trait WireFormat: Sized {
+    fn encode(&self, w: &mut impl Write) -> io::Result<()>;
+    fn decode(r: &mut impl Read) -> io::Result<Self>;
+}
+
+macro_rules! wire_format {
+    ($name:ident { $($field:ident : $ty:ty),* $(,)? }) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name {
+            $(pub $field: $ty,)*
+        }
+
+        impl WireFormat for $name {
+            fn encode(&self, w: &mut impl Write) -> io::Result<()> {
+                $(w.write_all(&self.$field.to_le_bytes())?;)*
+                Ok(())
+            }
+
+            fn decode(r: &mut impl Read) -> io::Result<Self> {
+                $(
+                    let mut buf = [0u8; std::mem::size_of::<$ty>()];
+                    r.read_exact(&mut buf)?;
+                    let $field = <$ty>::from_le_bytes(buf);
+                )*
+                Ok($name { $($field,)* })
+            }
+        }
+    };
+}
+
+wire_format!(Header {
+    magic: u32,
+    cmd: u8,
+    flags: u8,
+    reserved: u16,
+    req_id: u32,
+    payload_len: u32,
+});
+
+wire_format!(ReadRequestHeader {
+    offset: u64,
+    length: u64,
+});
+
+wire_format!(WriteRequestHeader {
+    offset: u64,
+    data_len: u64,
+});
+
+wire_format!(TruncateRequestHeader {
+    new_len: u64,
+});
+
+wire_format!(ReadDirRequestHeader {
+    cursor: u64,
+});
+
+wire_format!(ReadResponseHeader {
+    offset: u64,
+    uncompressed_len: u64,
+});
+
+wire_format!(DirEntryHeader {
+    size: u64,
+    entry_type: u8,
+    mtime: u64,
+});
+
+wire_format!(StatResponseHeader {
+    file_size: u64,
+    chunk_count: u32,
+});
+
+wire_format!(ChunkManifestEntryHeader {
+    offset: u64,
+    len: u64,
+});
+
+/// Writes a `len: u16 | bytes` length-prefixed field, used for every path
+/// and name in the protocol.
+pub fn write_len_prefixed(w: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    w.write_all(bytes)
+}
+
+/// Reads a `len: u16 | bytes` length-prefixed field back.
+pub fn read_len_prefixed(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 2];
+    r.read_exact(&mut len_buf)?;
+    let len = u16::from_le_bytes(len_buf) as usize;
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads exactly `len` bytes, used for a trailing field whose length was
+/// carried earlier in the payload (e.g. the data in `CMD_WRITE`).
+pub fn read_exact_len(r: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Writes a fixed-width 32-byte hash (e.g. a BLAKE3 digest) verbatim.
+pub fn write_hash(w: &mut impl Write, hash: &[u8; 32]) -> io::Result<()> {
+    w.write_all(hash)
+}
+
+/// Reads whatever is left in `r` to the end — used for the trailing field in
+/// commands where that field has no length prefix because nothing follows it
+/// (e.g. the path in `CMD_READ`, the message in `CMD_ERROR`).
+pub fn read_to_end(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn round_trip<T: WireFormat + PartialEq + std::fmt::Debug>(value: T) {
+        let mut buf = Vec::new();
+        value.encode(&mut buf).unwrap();
+        let decoded = T::decode(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(value, decoded);
+    }
+
+    #[test]
+    fn header_round_trips() {
+        round_trip(Header { magic: 0x5245504C, cmd: 2, flags: 3, reserved: 0, req_id: 42, payload_len: 128 });
+    }
+
+    #[test]
+    fn read_request_header_round_trips() {
+        round_trip(ReadRequestHeader { offset: 0, length: u64::MAX });
+    }
+
+    #[test]
+    fn write_request_header_round_trips() {
+        round_trip(WriteRequestHeader { offset: 4096, data_len: 8192 });
+    }
+
+    #[test]
+    fn truncate_request_header_round_trips() {
+        round_trip(TruncateRequestHeader { new_len: 0 });
+    }
+
+    #[test]
+    fn read_dir_request_header_round_trips() {
+        round_trip(ReadDirRequestHeader { cursor: 7 });
+    }
+
+    #[test]
+    fn read_response_header_round_trips() {
+        round_trip(ReadResponseHeader { offset: 1024, uncompressed_len: 2048 });
+    }
+
+    #[test]
+    fn dir_entry_header_round_trips() {
+        round_trip(DirEntryHeader { size: 99, entry_type: 1, mtime: 1_700_000_000 });
+    }
+
+    #[test]
+    fn stat_response_header_round_trips() {
+        round_trip(StatResponseHeader { file_size: 123_456, chunk_count: 16 });
+    }
+
+    #[test]
+    fn chunk_manifest_entry_header_round_trips() {
+        round_trip(ChunkManifestEntryHeader { offset: 8 * 1024 * 1024, len: 4096 });
+    }
+
+    #[test]
+    fn len_prefixed_round_trips() {
+        let mut buf = Vec::new();
+        write_len_prefixed(&mut buf, b"/home/user/model.bin").unwrap();
+        let decoded = read_len_prefixed(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(decoded, b"/home/user/model.bin");
+    }
+
+    #[test]
+    fn len_prefixed_empty_round_trips() {
+        let mut buf = Vec::new();
+        write_len_prefixed(&mut buf, b"").unwrap();
+        let decoded = read_len_prefixed(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(decoded, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn read_exact_len_reads_requested_bytes_only() {
+        let mut cursor = Cursor::new(b"hello world".to_vec());
+        let data = read_exact_len(&mut cursor, 5).unwrap();
+        assert_eq!(data, b"hello");
+        // Anything past `len` is left unconsumed for the caller.
+        assert_eq!(read_to_end(&mut cursor).unwrap(), b" world");
+    }
+
+    #[test]
+    fn read_exact_len_errors_when_short() {
+        let mut cursor = Cursor::new(b"ab".to_vec());
+        assert!(read_exact_len(&mut cursor, 10).is_err());
+    }
+
+    #[test]
+    fn write_hash_round_trips_via_read_to_end() {
+        let hash = [7u8; 32];
+        let mut buf = Vec::new();
+        write_hash(&mut buf, &hash).unwrap();
+        assert_eq!(read_to_end(&mut Cursor::new(buf)).unwrap(), hash.to_vec());
+    }
+
+    #[test]
+    fn decode_errors_on_truncated_input() {
+        let buf = vec![0u8; 3];
+        assert!(Header::decode(&mut Cursor::new(buf)).is_err());
+    }
+}